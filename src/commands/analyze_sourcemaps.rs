@@ -2,6 +2,9 @@
 use std::cell::Ref;
 use std::env;
 use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
 
 use prelude::*;
 use api::Api;
@@ -10,9 +13,14 @@ use utils::ArgExt;
 
 use clap::{App, Arg, ArgMatches};
 use url::Url;
-use html5ever::rcdom::{Document, Element, Handle, Node};
+use html5ever::driver::ParseOpts;
+use html5ever::rcdom::{Document, Element, Handle, Node, RcDom};
+use html5ever::tendril::TendrilSink;
 use colored::Colorize;
+use futures::future;
+use futures_cpupool::CpuPool;
 use might_be_minified;
+use serde_json;
 use sourcemap;
 use walkdir;
 
@@ -23,7 +31,329 @@ pub fn make_app<'a, 'b: 'a>(app: App<'a, 'b>) -> App<'a, 'b> {
             .required(true)
             .value_name("URL")
             .index(1)
-            .help("the URL to analyze"))
+            .help("the URL, or a local HTML file / file:// URL, to analyze"))
+        .arg(Arg::with_name("asset_root")
+            .long("asset-root")
+            .value_name("DIR")
+            .help("resolve <script src> and sourcemap references against this directory \
+                   instead of fetching them over HTTP"))
+        .arg(Arg::with_name("concurrency")
+            .long("concurrency")
+            .value_name("N")
+            .help("number of scripts to fetch concurrently [default: 4]"))
+        .arg(Arg::with_name("ignore_domain")
+            .long("ignore-domain")
+            .value_name("GLOB")
+            .multiple(true)
+            .number_of_values(1)
+            .help("ignore scripts served from hosts matching this glob (can be repeated)"))
+        .arg(Arg::with_name("only_domain")
+            .long("only-domain")
+            .value_name("GLOB")
+            .multiple(true)
+            .number_of_values(1)
+            .help("only analyze scripts served from hosts matching this glob (can be repeated)"))
+        .arg(Arg::with_name("no_default_ignores")
+            .long("no-default-ignores")
+            .help("do not skip the built-in list of community CDN hosts"))
+        .arg(Arg::with_name("format")
+            .long("format")
+            .value_name("FORMAT")
+            .possible_values(&["text", "json"])
+            .default_value("text")
+            .help("output format; use json to consume the report in CI"))
+}
+
+/// A host glob such as `*.example.com` or `cdn.example.com`.
+fn domain_matches_glob(domain: &str, pattern: &str) -> bool {
+    if pattern.starts_with("*.") {
+        let suffix = &pattern[2..];
+        domain == suffix || domain.ends_with(&format!(".{}", suffix))
+    } else {
+        domain == pattern
+    }
+}
+
+/// Why a script's host was excluded from analysis.
+enum IgnoreReason {
+    CommunityCdn,
+    NotInAllowlist,
+    MatchedIgnoreDomain,
+}
+
+impl IgnoreReason {
+    fn message(&self) -> &'static str {
+        match *self {
+            IgnoreReason::CommunityCdn => "known community CDN provided script; ignoring",
+            IgnoreReason::NotInAllowlist => "script host not in --only-domain allowlist; ignoring",
+            IgnoreReason::MatchedIgnoreDomain => "script host matched --ignore-domain; ignoring",
+        }
+    }
+
+    /// The `ScriptStatus` a skipped script should be reported as, so
+    /// `--format json` consumers can tell a deliberate user filter apart
+    /// from the built-in community-CDN default.
+    fn status(&self) -> ScriptStatus {
+        match *self {
+            IgnoreReason::CommunityCdn => ScriptStatus::IgnoredCdn,
+            IgnoreReason::NotInAllowlist => ScriptStatus::IgnoredNotInAllowlist,
+            IgnoreReason::MatchedIgnoreDomain => ScriptStatus::IgnoredByDomainFilter,
+        }
+    }
+}
+
+/// Resolves `--ignore-domain` / `--only-domain` / `--no-default-ignores`
+/// into a decision of whether a given script should be analyzed.
+#[derive(Clone)]
+struct DomainFilter {
+    only: Vec<String>,
+    ignore: Vec<String>,
+    use_default_ignores: bool,
+}
+
+impl DomainFilter {
+    fn from_matches(matches: &ArgMatches) -> DomainFilter {
+        DomainFilter {
+            only: matches.values_of("only_domain")
+                .map(|v| v.map(|s| s.to_string()).collect())
+                .unwrap_or_else(Vec::new),
+            ignore: matches.values_of("ignore_domain")
+                .map(|v| v.map(|s| s.to_string()).collect())
+                .unwrap_or_else(Vec::new),
+            use_default_ignores: !matches.is_present("no_default_ignores"),
+        }
+    }
+
+    fn ignore_reason(&self, url: &Url) -> Option<IgnoreReason> {
+        let domain = url.domain().unwrap_or("");
+
+        if !self.only.is_empty() {
+            if self.only.iter().any(|p| domain_matches_glob(domain, p)) {
+                return None;
+            }
+            return Some(IgnoreReason::NotInAllowlist);
+        }
+
+        if self.ignore.iter().any(|p| domain_matches_glob(domain, p)) {
+            return Some(IgnoreReason::MatchedIgnoreDomain);
+        }
+
+        if self.use_default_ignores && is_community_cdn_url(url) {
+            return Some(IgnoreReason::CommunityCdn);
+        }
+
+        None
+    }
+}
+
+/// Bounds how many fetches are in flight at once.
+///
+/// Acquiring blocks the calling thread until a permit is free, which is fine
+/// here since every permit holder is already running on a `CpuPool` thread
+/// dedicated to blocking HTTP calls.
+struct Semaphore {
+    permits: Mutex<usize>,
+    cvar: Condvar,
+}
+
+impl Semaphore {
+    fn new(permits: usize) -> Semaphore {
+        Semaphore {
+            permits: Mutex::new(permits),
+            cvar: Condvar::new(),
+        }
+    }
+
+    fn acquire(self: &Arc<Self>) -> SemaphorePermit {
+        let mut permits = self.permits.lock().unwrap();
+        while *permits == 0 {
+            permits = self.cvar.wait(permits).unwrap();
+        }
+        *permits -= 1;
+        SemaphorePermit { sem: self.clone() }
+    }
+
+    fn release(&self) {
+        let mut permits = self.permits.lock().unwrap();
+        *permits += 1;
+        self.cvar.notify_one();
+    }
+}
+
+struct SemaphorePermit {
+    sem: Arc<Semaphore>,
+}
+
+impl Drop for SemaphorePermit {
+    fn drop(&mut self) {
+        self.sem.release();
+    }
+}
+
+/// A fetched resource, normalized whether it came over HTTP or off disk.
+struct FetchResponse {
+    url: Url,
+    status: u32,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl FetchResponse {
+    fn ok(&self) -> bool {
+        self.status < 400
+    }
+
+    fn failed(&self) -> bool {
+        !self.ok()
+    }
+
+    fn status(&self) -> u32 {
+        self.status
+    }
+
+    fn url(&self) -> &Url {
+        &self.url
+    }
+
+    fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|&&(ref k, _)| k.eq_ignore_ascii_case(name))
+            .map(|&(_, ref v)| v.as_str())
+    }
+
+    fn to_result(self) -> Result<FetchResponse> {
+        if self.failed() {
+            Err(format!("request to {} failed ({})", self.url, self.status).into())
+        } else {
+            Ok(self)
+        }
+    }
+
+    fn body_as_bytes(&self) -> Result<Vec<u8>> {
+        Ok(self.body.clone())
+    }
+
+    fn body_as_string(&self) -> Result<String> {
+        Ok(String::from_utf8_lossy(&self.body).into_owned())
+    }
+}
+
+fn parse_html(body: &[u8]) -> Result<RcDom> {
+    Ok(::html5ever::parse_document(RcDom::default(), ParseOpts::default())
+        .from_utf8()
+        .read_from(&mut &body[..])?)
+}
+
+/// Resolves a local path for a discovered `file://` script or sourcemap
+/// URL. An ordinary reference relative to the page (one whose joined path
+/// still lives under the page's own directory, `page_dir`) is read from
+/// that literal path, since it's already pointing at the right file. A
+/// genuinely root-relative reference (one whose join replaced the page's
+/// whole path, e.g. `/static/app.js`) is instead resolved against
+/// `asset_root`, since read literally it would resolve to the filesystem
+/// root instead of the dist directory the user meant.
+fn local_path_for_url(url: &Url, page_dir: Option<&Url>, asset_root: Option<&PathBuf>) -> Result<PathBuf> {
+    if let Some(dir) = page_dir {
+        if url.path().starts_with(dir.path()) {
+            return url.to_file_path().map_err(|_| "invalid file:// URL".into());
+        }
+    }
+    if let Some(root) = asset_root {
+        return Ok(root.join(url.path().trim_start_matches('/')));
+    }
+    if page_dir.is_none() {
+        if let Ok(path) = url.to_file_path() {
+            return Ok(path);
+        }
+    }
+    Err(format!("cannot resolve {} locally without --asset-root", url).into())
+}
+
+/// Fetches scripts, sourcemaps, and scrape probes. Whether a given URL is
+/// read straight off the local filesystem or fetched over HTTP is decided
+/// per call, from that URL's own scheme -- not once for the whole run --
+/// so a locally saved page can still reference a script on a real CDN, and
+/// any `file://` reference a live page happens to produce can still be
+/// read back via `--asset-root`.
+struct Resolver {
+    /// `None` only in tests that never need to make an HTTP request.
+    api: Option<Api>,
+    asset_root: Option<PathBuf>,
+    /// Directory of the page being analyzed, when it's a local `file://`
+    /// entry; used to tell an ordinary relative script/sourcemap reference
+    /// apart from a root-relative one in `local_path_for_url`.
+    page_dir: Option<Url>,
+}
+
+impl Resolver {
+    fn new(config: &Config, asset_root: Option<PathBuf>, page_dir: Option<Url>) -> Resolver {
+        Resolver { api: Some(Api::new(config)), asset_root, page_dir }
+    }
+
+    fn get(&self, url: &Url) -> Result<FetchResponse> {
+        if url.scheme() == "file" {
+            return self.get_local(url);
+        }
+
+        let api = self.api.as_ref().ok_or_else(|| format!("cannot fetch {} without network access", url))?;
+        let resp = api.get_handle_redirect(&url.to_string())?;
+        let headers = ["sourcemap", "x-sourcemap"].iter()
+            .filter_map(|&name| resp.get_header(name).map(|v| (name.to_string(), v.to_string())))
+            .collect();
+        let status = resp.status();
+        let resolved_url = resp.url().clone();
+        let body = if status < 400 { resp.to_result()?.body_as_bytes()? } else { vec![] };
+        Ok(FetchResponse { url: resolved_url, status, headers, body })
+    }
+
+    fn head(&self, url: &Url) -> Result<FetchResponse> {
+        if url.scheme() == "file" {
+            return self.get_local(url);
+        }
+
+        let api = self.api.as_ref().ok_or_else(|| format!("cannot fetch {} without network access", url))?;
+        let resp = api.head(&url.to_string())?;
+        Ok(FetchResponse {
+            url: resp.url().clone(),
+            status: resp.status(),
+            headers: vec![],
+            body: vec![],
+        })
+    }
+
+    fn get_local(&self, url: &Url) -> Result<FetchResponse> {
+        let path = local_path_for_url(url, self.page_dir.as_ref(), self.asset_root.as_ref())?;
+        match fs::read(&path) {
+            Ok(body) => Ok(FetchResponse { url: url.clone(), status: 200, headers: vec![], body }),
+            Err(_) => Ok(FetchResponse { url: url.clone(), status: 404, headers: vec![], body: vec![] }),
+        }
+    }
+
+    /// Fetches the entry page itself. Unlike `get`, a local entry is always
+    /// read from the literal path given on the command line: `--asset-root`
+    /// only applies to the scripts and sourcemaps *referenced by* that page,
+    /// not to the page argument itself.
+    fn get_entry(&self, url: &Url) -> Result<FetchResponse> {
+        if url.scheme() != "file" {
+            return self.get(url);
+        }
+        let path = url.to_file_path().map_err(|_| format!("invalid local path: {}", url))?;
+        match fs::read(&path) {
+            Ok(body) => Ok(FetchResponse { url: url.clone(), status: 200, headers: vec![], body }),
+            Err(_) => Ok(FetchResponse { url: url.clone(), status: 404, headers: vec![], body: vec![] }),
+        }
+    }
+}
+
+/// Turns the positional argument into the URL to analyze: a real URL is
+/// used as-is, anything else is treated as a local filesystem path and
+/// turned into a `file://` URL.
+fn resolve_input_url(input: &str) -> Result<Url> {
+    if let Ok(url) = Url::parse(input) {
+        return Ok(url);
+    }
+    let path = env::current_dir()?.join(input);
+    Url::from_file_path(&path).map_err(|_| format!("{} is not a valid URL or local path", input).into())
 }
 
 fn is_community_cdn_url(url: &Url) -> bool {
@@ -68,55 +398,285 @@ fn find_scripts(url: &str, handle: &Handle) -> Result<Vec<Url>> {
     Ok(rv)
 }
 
-fn validate_sourcemap(api: &Api, url: &Url, body: &[u8]) -> Result<()> {
-    let prefix = "      ";
-    let sourcemap = match sourcemap::DecodedMap::from_reader(body)? {
-        sourcemap::DecodedMap::Regular(sm) => {
-            println!("{}sourcemap type: {}", prefix, "regular".cyan());
-            sm
+/// `regular` vs `index` sourcemaps, as reported in the JSON report.
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+enum SourcemapKind {
+    Regular,
+    Index,
+}
+
+/// Whether a missing embedded source could be scraped from its reference URL.
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+enum ScrapeStatus {
+    Scrapable { url: String },
+    NotScrapable { url: String, http_status: u32 },
+}
+
+/// A problem found while validating a sourcemap's source content.
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+enum SourceWarning {
+    MissingSource { source: String, scrape_status: ScrapeStatus },
+    InvalidSourceReference { index: u32 },
+}
+
+/// A token whose mapping doesn't actually resolve to real source content.
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "kebab-case")]
+enum MappingError {
+    SourceIndexOutOfRange { token_index: u32, src_index: u32 },
+    SourcePositionOutOfRange { token_index: u32, source: String, src_line: u32, src_col: u32 },
+}
+
+/// Where a listed source would be scraped from, after resolving `sourceRoot`.
+#[derive(Serialize)]
+struct ResolvedSource {
+    source: String,
+    resolved_url: String,
+}
+
+/// Validation results for a single sourcemap.
+#[derive(Serialize)]
+struct SourcemapReport {
+    kind: SourcemapKind,
+    source_count: u32,
+    token_count: u32,
+    warnings: Vec<SourceWarning>,
+    mapping_errors: Vec<MappingError>,
+    resolved_sources: Vec<ResolvedSource>,
+    /// Percentage of the generated file's lines that have at least one mapping.
+    coverage_percent: f64,
+}
+
+impl SourcemapReport {
+    fn has_mapping_errors(&self) -> bool {
+        !self.mapping_errors.is_empty()
+    }
+}
+
+/// What became of a single discovered `<script>`.
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+enum ScriptStatus {
+    IgnoredCdn,
+    IgnoredNotInAllowlist,
+    IgnoredByDomainFilter,
+    FetchFailed { http_status: u32 },
+    Unminified,
+    MinifiedWithSourcemap { sourcemap_url: String, sourcemap: SourcemapReport },
+    MinifiedMissingSourcemap { sourcemap_url: Option<String>, http_status: Option<u32> },
+}
+
+impl ScriptStatus {
+    fn is_missing_sourcemap(&self) -> bool {
+        match *self {
+            ScriptStatus::MinifiedMissingSourcemap { .. } => true,
+            _ => false,
         }
+    }
+
+    fn has_mapping_errors(&self) -> bool {
+        match *self {
+            ScriptStatus::MinifiedWithSourcemap { ref sourcemap, .. } => sourcemap.has_mapping_errors(),
+            _ => false,
+        }
+    }
+}
+
+/// A single script's analysis, as both human-readable output and the
+/// structured status consumed by `--format json`.
+struct ScriptReport {
+    script_url: Url,
+    status: ScriptStatus,
+    text: String,
+}
+
+/// Resolves a (possibly relative) source path against the sourcemap's
+/// `sourceRoot`, then against the sourcemap's own URL, to get the URL that
+/// would actually be scraped for that source's content.
+fn resolve_source_url(sm_url: &Url, source_root: Option<&str>, source: &str) -> Result<Url> {
+    // A source that's already absolute -- a full URL (including pseudo-URLs
+    // like `webpack://...`) or a root-relative path -- stands on its own;
+    // joining it with sourceRoot would only produce a malformed reference.
+    let is_absolute = Url::parse(source).is_ok() || source.starts_with('/');
+    let joined = match source_root {
+        Some(root) if !root.is_empty() && !is_absolute =>
+            format!("{}/{}", root.trim_end_matches('/'), source.trim_start_matches('/')),
+        _ => source.to_string(),
+    };
+    Ok(sm_url.join(&joined)?)
+}
+
+fn validate_sourcemap(resolver: &Resolver, url: &Url, body: &[u8], generated_source: &str) -> Result<SourcemapReport> {
+    let (kind, sourcemap) = match sourcemap::DecodedMap::from_reader(body)? {
+        sourcemap::DecodedMap::Regular(sm) => (SourcemapKind::Regular, sm),
         sourcemap::DecodedMap::Index(sm) => {
-            println!("{}sourcemap type: {}", prefix, "index".yellow());
             match sm.flatten() {
-                Ok(sm) => sm,
-                Err(err) => {
-                    println!("{}{}", prefix, "unsupported sourcemap index".red());
-                    return Err(err.into());
-                }
+                Ok(sm) => (SourcemapKind::Index, sm),
+                Err(err) => return Err(err.into()),
             }
         }
     };
 
-    println!("{}sources: {}", prefix, sourcemap.get_source_count().to_string().yellow());
-    println!("{}tokens: {}", prefix, sourcemap.get_token_count().to_string().yellow());
+    let source_count = sourcemap.get_source_count();
+    let source_root = sourcemap.get_source_root();
 
+    let mut warnings = vec![];
     for (idx, contents) in sourcemap.source_contents().enumerate() {
         let source_url = sourcemap.get_source(idx as u32);
         if contents.is_none() {
             if let Some(ref source_ref) = source_url {
-                println!("{}  {}: no embedded sourcecode for {}", prefix,
-                         "warning".yellow(),
-                         source_ref.cyan());
-                let sourcecode_url = url.join(source_ref)?;
-                let resp = api.head(&sourcecode_url.to_string())?;
-                if resp.ok() {
-                    println!("{}  (but can scrape source at {})", prefix, resp.url().to_string().cyan());
+                let sourcecode_url = resolve_source_url(url, source_root, source_ref)?;
+                let resp = resolver.head(&sourcecode_url)?;
+                let scrape_status = if resp.ok() {
+                    ScrapeStatus::Scrapable { url: resp.url().to_string() }
                 } else {
-                    println!("{}  ({}: cannot scrape at {} [{}])",
-                             prefix, "error".red(), resp.url().to_string().cyan(), resp.status());
-                }
+                    ScrapeStatus::NotScrapable {
+                        url: resp.url().to_string(),
+                        http_status: resp.status(),
+                    }
+                };
+                warnings.push(SourceWarning::MissingSource {
+                    source: source_ref.to_string(),
+                    scrape_status,
+                });
             } else {
-                println!("{}  {}: invalid source reference {}", prefix,
-                         "warning".yellow(),
-                         format!("#{}", idx).cyan());
+                warnings.push(SourceWarning::InvalidSourceReference { index: idx as u32 });
             }
         }
     }
 
-    Ok(())
+    let mut resolved_sources = vec![];
+    for idx in 0..source_count {
+        if let Some(source) = sourcemap.get_source(idx) {
+            if let Ok(resolved_url) = resolve_source_url(url, source_root, source) {
+                resolved_sources.push(ResolvedSource {
+                    source: source.to_string(),
+                    resolved_url: resolved_url.to_string(),
+                });
+            }
+        }
+    }
+
+    // Per-source line lists, so out-of-range tokens can be flagged without
+    // re-splitting the same embedded source content for every token.
+    let source_lines: Vec<Option<Vec<&str>>> = sourcemap.source_contents()
+        .map(|contents| contents.map(|s| s.lines().collect()))
+        .collect();
+
+    let mut mapping_errors = vec![];
+    let mut covered_dst_lines = HashSet::new();
+    for (token_index, token) in sourcemap.tokens().enumerate() {
+        covered_dst_lines.insert(token.get_dst_line());
+
+        let src_id = token.get_src_id();
+        if src_id == !0 {
+            continue;
+        }
+
+        if src_id >= source_count {
+            mapping_errors.push(MappingError::SourceIndexOutOfRange {
+                token_index: token_index as u32,
+                src_index: src_id,
+            });
+            continue;
+        }
+
+        if let Some(&Some(ref lines)) = source_lines.get(src_id as usize) {
+            let src_line = token.get_src_line() as usize;
+            let out_of_range = match lines.get(src_line) {
+                None => true,
+                Some(line) => (token.get_src_col() as usize) > line.chars().count(),
+            };
+            if out_of_range {
+                mapping_errors.push(MappingError::SourcePositionOutOfRange {
+                    token_index: token_index as u32,
+                    source: token.get_source().unwrap_or("").to_string(),
+                    src_line: token.get_src_line(),
+                    src_col: token.get_src_col(),
+                });
+            }
+        }
+    }
+
+    let generated_line_count = generated_source.lines().count();
+    let coverage_percent = if generated_line_count == 0 {
+        0.0
+    } else {
+        (covered_dst_lines.len() as f64 / generated_line_count as f64) * 100.0
+    };
+
+    Ok(SourcemapReport {
+        kind,
+        source_count,
+        token_count: sourcemap.get_token_count(),
+        warnings,
+        mapping_errors,
+        resolved_sources,
+        coverage_percent,
+    })
+}
+
+fn render_sourcemap_report(report: &SourcemapReport, out: &mut String) {
+    let prefix = "      ";
+
+    match report.kind {
+        SourcemapKind::Regular => out.push_str(&format!("{}sourcemap type: {}\n", prefix, "regular".cyan())),
+        SourcemapKind::Index => out.push_str(&format!("{}sourcemap type: {}\n", prefix, "index".yellow())),
+    }
+
+    out.push_str(&format!("{}sources: {}\n", prefix, report.source_count.to_string().yellow()));
+    out.push_str(&format!("{}tokens: {}\n", prefix, report.token_count.to_string().yellow()));
+    out.push_str(&format!("{}coverage: {}\n", prefix,
+        format!("{:.1}%", report.coverage_percent).yellow()));
+
+    if !report.resolved_sources.is_empty() {
+        out.push_str(&format!("{}sourceRoot resolution:\n", prefix));
+        for resolved in &report.resolved_sources {
+            out.push_str(&format!("{}  {} -> {}\n", prefix,
+                     resolved.source.cyan(), resolved.resolved_url.cyan()));
+        }
+    }
+
+    for warning in &report.warnings {
+        match *warning {
+            SourceWarning::MissingSource { ref source, ref scrape_status } => {
+                out.push_str(&format!("{}  {}: no embedded sourcecode for {}\n", prefix,
+                         "warning".yellow(), source.cyan()));
+                match *scrape_status {
+                    ScrapeStatus::Scrapable { ref url } => {
+                        out.push_str(&format!("{}  (but can scrape source at {})\n", prefix, url.cyan()));
+                    }
+                    ScrapeStatus::NotScrapable { ref url, http_status } => {
+                        out.push_str(&format!("{}  ({}: cannot scrape at {} [{}])\n",
+                                 prefix, "error".red(), url.cyan(), http_status));
+                    }
+                }
+            }
+            SourceWarning::InvalidSourceReference { index } => {
+                out.push_str(&format!("{}  {}: invalid source reference {}\n", prefix,
+                         "warning".yellow(), format!("#{}", index).cyan()));
+            }
+        }
+    }
+
+    for mapping_error in &report.mapping_errors {
+        match *mapping_error {
+            MappingError::SourceIndexOutOfRange { token_index, src_index } => {
+                out.push_str(&format!("{}  {}: token #{} references out-of-range source {}\n", prefix,
+                         "error".red(), token_index, src_index));
+            }
+            MappingError::SourcePositionOutOfRange { token_index, ref source, src_line, src_col } => {
+                out.push_str(&format!("{}  {}: token #{} points past the end of {} ({}:{})\n", prefix,
+                         "error".red(), token_index, source.cyan(), src_line, src_col));
+            }
+        }
+    }
 }
 
-fn explain_upload_commands(sourcemaps: &[(Url, Option<Url>, bool)]) -> Result<()> {
+fn explain_upload_commands(sourcemaps: &[(Url, Option<Url>)]) -> Result<()> {
     let prefix = "  ";
 
     let known_js_files: HashSet<String> = sourcemaps
@@ -147,7 +707,7 @@ fn explain_upload_commands(sourcemaps: &[(Url, Option<Url>, bool)]) -> Result<()
 
     println!("{:?}", interesting_folders);
 
-    for &(ref script_url, ref sm_ref, missing) in sourcemaps {
+    for &(ref script_url, ref sm_ref) in sourcemaps {
         println!("{}◦ {}", prefix, script_url.to_string().cyan());
         if let &Some(ref sm_ref) = sm_ref {
             println!("{}  -> {}", prefix, sm_ref.to_string().magenta());
@@ -157,102 +717,369 @@ fn explain_upload_commands(sourcemaps: &[(Url, Option<Url>, bool)]) -> Result<()
     Ok(())
 }
 
-pub fn execute<'a>(matches: &ArgMatches<'a>, config: &Config) -> Result<()> {
-    let url = Url::parse(matches.value_of("url").unwrap())?;
-    let url_str = url.to_string();
-    let api = Api::new(config);
-
-    println!("› Finding scripts on {}", url_str.cyan());
+/// Fetches a single script (and its referenced sourcemap, if any), returning
+/// both its rendered human-readable output and its structured status.
+fn analyze_script(resolver: &Resolver, filter: &DomainFilter, script_url: &Url) -> Result<ScriptReport> {
+    let mut out = String::new();
+    let script_url_str = script_url.to_string();
 
-    let resp = api.get_handle_redirect(&url_str)?.to_result()?;
-    if resp.url() != &url {
-        println!("› Redirected to {}", resp.url().to_string().cyan());
+    if let Some(reason) = filter.ignore_reason(script_url) {
+        out.push_str(&format!("  Ⅰ {}\n", script_url_str.yellow()));
+        out.push_str(&format!("    {}\n", reason.message()));
+        return Ok(ScriptReport { script_url: script_url.clone(), status: reason.status(), text: out });
     }
 
-    let html = resp.parse_html()?;
-    let scripts = find_scripts(&resp.url().to_string(), &html.document)?;
+    let resp = resolver.get(script_url)?;
 
-    println!("› Scripts referenced:");
-    for script_url in &scripts {
-        println!("  ◦ {}", script_url.to_string().cyan());
+    if resp.failed() {
+        out.push_str(&format!("  ✕ {} [{}]\n", script_url_str.red(), resp.status()));
+        return Ok(ScriptReport {
+            script_url: script_url.clone(),
+            status: ScriptStatus::FetchFailed { http_status: resp.status() },
+            text: out,
+        });
     }
 
-    let mut sourcemaps = vec![];
-    let mut missing_sourcemaps = 0;
+    out.push_str(&format!("  ✓ {}\n", script_url_str.green()));
 
-    println!("› Analyzing scripts:");
-    for script_url in &scripts {
-        let script_url_str = script_url.to_string();
-        if is_community_cdn_url(script_url) {
-            println!("  Ⅰ {}", script_url_str.yellow());
-            println!("    known community CDN provided script; ignoring");
-            continue;
+    let mut sm_ref_url = resp.get_header("sourcemap").or_else(|| {
+        resp.get_header("x-sourcemap")
+    }).map(|x| x.to_string());
+    let generated_source = resp.body_as_string()?;
+    if sm_ref_url.is_none() {
+        let sm_ref = sourcemap::locate_sourcemap_reference_slice(generated_source.as_bytes()).unwrap();
+        sm_ref_url = sm_ref.get_url().map(|x| x.to_string());
+    }
+
+    if sm_ref_url.is_some() || might_be_minified::analyze_str(&generated_source).is_likely_minified() {
+        if let Some(ref url) = sm_ref_url {
+            let sm_url = script_url.join(url)?;
+            let sm_url_str = sm_url.to_string();
+            out.push_str(&format!("    minified {} sourcemap (-> {})\n", "with".green(), url.cyan()));
+            let resp = resolver.get(&sm_url)?;
+            if resp.failed() {
+                out.push_str(&format!("    ✕ {} [{}]\n", sm_url_str.red(), resp.status()));
+                Ok(ScriptReport {
+                    script_url: script_url.clone(),
+                    status: ScriptStatus::MinifiedMissingSourcemap {
+                        sourcemap_url: Some(sm_url_str),
+                        http_status: Some(resp.status()),
+                    },
+                    text: out,
+                })
+            } else {
+                out.push_str(&format!("    ✓ {}\n", sm_url_str.green()));
+                let body = resp.body_as_bytes()?;
+                if sourcemap::is_sourcemap_slice(&body) {
+                    match validate_sourcemap(resolver, &sm_url, &body, &generated_source) {
+                        Ok(report) => {
+                            render_sourcemap_report(&report, &mut out);
+                            Ok(ScriptReport {
+                                script_url: script_url.clone(),
+                                status: ScriptStatus::MinifiedWithSourcemap { sourcemap_url: sm_url_str, sourcemap: report },
+                                text: out,
+                            })
+                        }
+                        Err(err) => {
+                            out.push_str(&format!("      {}: {}\n", "error parsing sourcemap".red(), err));
+                            Ok(ScriptReport {
+                                script_url: script_url.clone(),
+                                status: ScriptStatus::MinifiedMissingSourcemap {
+                                    sourcemap_url: Some(sm_url_str),
+                                    http_status: None,
+                                },
+                                text: out,
+                            })
+                        }
+                    }
+                } else {
+                    out.push_str(&format!("      {} sourcemap\n", "not a valid".red()));
+                    Ok(ScriptReport {
+                        script_url: script_url.clone(),
+                        status: ScriptStatus::MinifiedMissingSourcemap {
+                            sourcemap_url: Some(sm_url_str),
+                            http_status: None,
+                        },
+                        text: out,
+                    })
+                }
+            }
+        } else {
+            out.push_str(&format!("    minified {} sourcemap reference\n", "without".red()));
+            Ok(ScriptReport {
+                script_url: script_url.clone(),
+                status: ScriptStatus::MinifiedMissingSourcemap { sourcemap_url: None, http_status: None },
+                text: out,
+            })
         }
+    } else {
+        out.push_str("    unminified\n");
+        Ok(ScriptReport { script_url: script_url.clone(), status: ScriptStatus::Unminified, text: out })
+    }
+}
 
-        let resp = api.get_handle_redirect(&script_url_str)?;
+/// Fetches every script (and its sourcemap) concurrently, bounded to
+/// `concurrency` in-flight requests via a counting semaphore, and returns
+/// results ordered by the original `scripts` index so output stays
+/// deterministic regardless of completion order.
+///
+/// `concurrency` must be at least 1 -- the caller is responsible for
+/// validating that, since a 0-permit semaphore would block every task on
+/// `sem.acquire()` forever.
+fn analyze_scripts_concurrently(
+    resolver: &Arc<Resolver>,
+    filter: &DomainFilter,
+    scripts: &[Url],
+    concurrency: usize,
+) -> Result<Vec<ScriptReport>> {
+    let pool = CpuPool::new(concurrency);
+    let sem = Arc::new(Semaphore::new(concurrency));
+    let filter = Arc::new(filter.clone());
 
-        if resp.failed() {
-            println!("  ✕ {} [{}]", script_url_str.red(), resp.status());
-            continue;
+    let tasks: Vec<_> = scripts.iter().cloned().enumerate().map(|(idx, script_url)| {
+        let resolver = resolver.clone();
+        let filter = filter.clone();
+        let sem = sem.clone();
+        pool.spawn_fn(move || -> Result<(usize, ScriptReport)> {
+            let _permit = sem.acquire();
+            let report = analyze_script(&resolver, &filter, &script_url)?;
+            Ok((idx, report))
+        })
+    }).collect();
+
+    let mut results = future::join_all(tasks).wait()?;
+    results.sort_by_key(|&(idx, _)| idx);
+    Ok(results.into_iter().map(|(_, report)| report).collect())
+}
+
+/// A single script's entry in the `--format json` report.
+#[derive(Serialize)]
+struct ScriptEntry {
+    script_url: String,
+    #[serde(flatten)]
+    status: ScriptStatus,
+}
+
+/// The document emitted by `--format json`.
+#[derive(Serialize)]
+struct AnalysisReport {
+    url: String,
+    redirected_to: Option<String>,
+    scripts: Vec<ScriptEntry>,
+    missing_sourcemaps: usize,
+    integrity_errors: usize,
+}
+
+pub fn execute<'a>(matches: &ArgMatches<'a>, config: &Config) -> Result<()> {
+    let url = resolve_input_url(matches.value_of("url").unwrap())?;
+    let url_str = url.to_string();
+    let asset_root = matches.value_of("asset_root").map(PathBuf::from);
+    let page_dir = if url.scheme() == "file" { Some(url.join(".")?) } else { None };
+    let resolver = Arc::new(Resolver::new(config, asset_root, page_dir));
+    let concurrency: usize = matches.value_of("concurrency").unwrap_or("4").parse()?;
+    if concurrency == 0 {
+        return Err("--concurrency must be at least 1".into());
+    }
+    let filter = DomainFilter::from_matches(matches);
+    let json_format = matches.value_of("format") == Some("json");
+
+    if !json_format {
+        println!("› Finding scripts on {}", url_str.cyan());
+    }
+
+    let resp = resolver.get_entry(&url)?.to_result()?;
+    let redirected_to = if resp.url() != &url {
+        if !json_format {
+            println!("› Redirected to {}", resp.url().to_string().cyan());
         }
+        Some(resp.url().to_string())
+    } else {
+        None
+    };
 
-        println!("  ✓ {}", script_url_str.green());
+    let html = parse_html(&resp.body_as_bytes()?)?;
+    let scripts = find_scripts(&resp.url().to_string(), &html.document)?;
 
-        let mut sm_ref_url = resp.get_header("sourcemap").or_else(|| {
-            resp.get_header("x-sourcemap")
-        }).map(|x| x.to_string());
-        let body = resp.to_result()?.body_as_string()?;
-        if sm_ref_url.is_none() {
-            let sm_ref = sourcemap::locate_sourcemap_reference_slice(body.as_bytes()).unwrap();
-            sm_ref_url = sm_ref.get_url().map(|x| x.to_string());
+    if !json_format {
+        println!("› Scripts referenced:");
+        for script_url in &scripts {
+            println!("  ◦ {}", script_url.to_string().cyan());
         }
+        println!("› Analyzing scripts:");
+    }
 
-        if sm_ref_url.is_some() || might_be_minified::analyze_str(&body).is_likely_minified() {
-            if let Some(ref url) = sm_ref_url {
-                let sm_url = script_url.join(url)?;
-                let sm_url_str = sm_url.to_string();
-                println!("    minified {} sourcemap (-> {})", "with".green(), url.cyan());
-                let resp = api.get_handle_redirect(&sm_url_str)?;
-                if resp.failed() {
-                    println!("    ✕ {} [{}]", sm_url_str.red(), resp.status());
-                    sourcemaps.push((script_url.clone(), Some(sm_url.clone()), false));
-                    missing_sourcemaps += 1;
-                } else {
-                    println!("    ✓ {}", sm_url_str.green());
-                    let body = resp.body_as_bytes()?;
-                    if sourcemap::is_sourcemap_slice(&body) {
-                        if let Err(err) = validate_sourcemap(&api, &sm_url, &body) {
-                            println!("      {}: {}", "error parsing sourcemap".red(), err);
-                        }
-                    } else {
-                        println!("      {} sourcemap", "not a valid".red());
-                    }
-                    sourcemaps.push((script_url.clone(), Some(sm_url.clone()), true));
+    let reports = analyze_scripts_concurrently(&resolver, &filter, &scripts, concurrency)?;
+    let missing_sourcemaps = reports.iter().filter(|r| r.status.is_missing_sourcemap()).count();
+    let integrity_errors = reports.iter().filter(|r| r.status.has_mapping_errors()).count();
+
+    if json_format {
+        let report = AnalysisReport {
+            url: url_str,
+            redirected_to,
+            scripts: reports.into_iter().map(|r| ScriptEntry {
+                script_url: r.script_url.to_string(),
+                status: r.status,
+            }).collect(),
+            missing_sourcemaps,
+            integrity_errors,
+        };
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        let mut sourcemaps = vec![];
+        for report in &reports {
+            print!("{}", report.text);
+            match report.status {
+                ScriptStatus::MinifiedWithSourcemap { ref sourcemap_url, .. } => {
+                    sourcemaps.push((report.script_url.clone(), Url::parse(sourcemap_url).ok()));
                 }
-            } else {
-                println!("    minified {} sourcemap reference", "without".red());
-                sourcemaps.push((script_url.clone(), None, false));
-                missing_sourcemaps += 1;
+                ScriptStatus::MinifiedMissingSourcemap { ref sourcemap_url, .. } => {
+                    sourcemaps.push((report.script_url.clone(), sourcemap_url.as_ref().and_then(|u| Url::parse(u).ok())));
+                }
+                _ => {}
             }
+        }
+
+        if missing_sourcemaps > 0 {
+            println!("› Found {} missing sourcemap(s) that need uploading",
+                     missing_sourcemaps.to_string().yellow());
         } else {
-            println!("    unminified");
+            println!("› No missing sourcemaps found!");
+            if !sourcemaps.is_empty() {
+                println!("  (but there are {} sourcemap(s) you should consider uploading)",
+                         sourcemaps.len().to_string().yellow());
+            }
+        }
+
+        if integrity_errors > 0 {
+            println!("› Found {} sourcemap(s) with bad mappings; see errors above",
+                     integrity_errors.to_string().red());
         }
-    }
 
-    if missing_sourcemaps > 0 {
-        println!("› Found {} missing sourcemap(s) that need uploading",
-                 missing_sourcemaps.to_string().yellow());
-    } else {
-        println!("› No missing sourcemaps found!");
         if !sourcemaps.is_empty() {
-            println!("  (but there are {} sourcemap(s) you should consider uploading)",
-                     sourcemaps.len().to_string().yellow());
+            explain_upload_commands(&sourcemaps)?;
         }
     }
 
-    if !sourcemaps.is_empty() {
-        explain_upload_commands(&sourcemaps)?;
+    if missing_sourcemaps > 0 || integrity_errors > 0 {
+        return Err(format!(
+            "found {} missing sourcemap(s) and {} sourcemap(s) with bad mappings",
+            missing_sourcemaps, integrity_errors
+        ).into());
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_domain_matches_glob_wildcard() {
+        assert!(domain_matches_glob("cdn.example.com", "*.example.com"));
+        assert!(domain_matches_glob("a.b.example.com", "*.example.com"));
+        assert!(domain_matches_glob("example.com", "*.example.com"));
+    }
+
+    #[test]
+    fn test_domain_matches_glob_wildcard_rejects_non_suffix_match() {
+        assert!(!domain_matches_glob("example.com.evil.com", "*.example.com"));
+        assert!(!domain_matches_glob("notexample.com", "*.example.com"));
+    }
+
+    #[test]
+    fn test_domain_matches_glob_exact() {
+        assert!(domain_matches_glob("example.com", "example.com"));
+        assert!(!domain_matches_glob("sub.example.com", "example.com"));
+    }
+
+    #[test]
+    fn test_resolve_source_url_without_source_root() {
+        let sm_url = Url::parse("https://example.com/static/app.js.map").unwrap();
+        let resolved = resolve_source_url(&sm_url, None, "app.js").unwrap();
+        assert_eq!(resolved.as_str(), "https://example.com/static/app.js");
+    }
+
+    #[test]
+    fn test_resolve_source_url_with_source_root() {
+        let sm_url = Url::parse("https://example.com/static/app.js.map").unwrap();
+        let resolved = resolve_source_url(&sm_url, Some("src/"), "./app.js").unwrap();
+        assert_eq!(resolved.as_str(), "https://example.com/static/src/app.js");
+    }
+
+    #[test]
+    fn test_resolve_source_url_with_absolute_source() {
+        let sm_url = Url::parse("https://example.com/static/app.js.map").unwrap();
+        let resolved = resolve_source_url(&sm_url, None, "/other/app.js").unwrap();
+        assert_eq!(resolved.as_str(), "https://example.com/other/app.js");
+    }
+
+    #[test]
+    fn test_resolve_source_url_ignores_source_root_for_absolute_sources() {
+        let sm_url = Url::parse("https://example.com/static/app.js.map").unwrap();
+
+        let resolved = resolve_source_url(&sm_url, Some("src/"), "https://other.com/vendor.js").unwrap();
+        assert_eq!(resolved.as_str(), "https://other.com/vendor.js");
+
+        let resolved = resolve_source_url(&sm_url, Some("src/"), "webpack:///app.js").unwrap();
+        assert_eq!(resolved.as_str(), "webpack:///app.js");
+
+        let resolved = resolve_source_url(&sm_url, Some("src/"), "/other/app.js").unwrap();
+        assert_eq!(resolved.as_str(), "https://example.com/other/app.js");
+    }
+
+    fn build_sourcemap(body: &mut Vec<u8>, add_out_of_range_source: bool) {
+        let mut builder = sourcemap::SourceMapBuilder::new(None);
+        let src_id = builder.add_source("a.js");
+        builder.set_source_contents(src_id, Some("line one\n"));
+        // Together with the token below, covers dst_lines 0 and 1 only, so
+        // coverage_percent should reflect that against a longer
+        // generated_source.
+        builder.add_raw(0, 0, 0, 0, Some(src_id), None);
+        // src_line 5 doesn't exist in "line one\n" -- should be flagged as
+        // SourcePositionOutOfRange.
+        builder.add_raw(1, 0, 5, 0, Some(src_id), None);
+        if add_out_of_range_source {
+            // No source was ever registered at index 1 -- should be flagged
+            // as SourceIndexOutOfRange.
+            builder.add_raw(2, 0, 0, 0, Some(1), None);
+        }
+        builder.into_sourcemap().to_writer(body).unwrap();
+    }
+
+    #[test]
+    fn test_validate_sourcemap_flags_out_of_range_positions_and_sources() {
+        let mut body = vec![];
+        build_sourcemap(&mut body, true);
+
+        let resolver = Resolver { api: None, asset_root: None, page_dir: None };
+        let url = Url::parse("https://example.com/static/app.js.map").unwrap();
+        let generated_source = "line a\nline b\nline c\n";
+
+        let report = validate_sourcemap(&resolver, &url, &body, generated_source).unwrap();
+
+        assert!(report.mapping_errors.iter().any(|e| match *e {
+            MappingError::SourcePositionOutOfRange { .. } => true,
+            _ => false,
+        }));
+        assert!(report.mapping_errors.iter().any(|e| match *e {
+            MappingError::SourceIndexOutOfRange { .. } => true,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn test_validate_sourcemap_coverage_percent() {
+        let mut body = vec![];
+        build_sourcemap(&mut body, false);
+
+        let resolver = Resolver { api: None, asset_root: None, page_dir: None };
+        let url = Url::parse("https://example.com/static/app.js.map").unwrap();
+        // Tokens cover dst_line 0 and 1 out of 4 generated lines -> 50%.
+        let generated_source = "line a\nline b\nline c\nline d\n";
+
+        let report = validate_sourcemap(&resolver, &url, &body, generated_source).unwrap();
+
+        assert!((report.coverage_percent - 50.0).abs() < f64::EPSILON);
+    }
+}